@@ -1,6 +1,153 @@
+use std::collections::HashMap;
 use std::fmt;
 use wain_ast::*;
 
+// The type of a single import or export at the module boundary, as seen by
+// the host: a function signature, a global's value type and mutability, or
+// the limits of a memory/table instance.
+#[derive(Clone, PartialEq)]
+#[cfg_attr(test, derive(Debug))]
+pub enum ExternType {
+    Func {
+        params: Vec<ValType>,
+        results: Vec<ValType>,
+    },
+    Global {
+        ty: ValType,
+        mutable: bool,
+    },
+    Memory {
+        min: u32,
+        max: Option<u32>,
+    },
+    Table {
+        min: u32,
+        max: Option<u32>,
+    },
+}
+
+impl fmt::Display for ExternType {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ExternType::Func { params, results } => {
+                let ps = params.iter().map(AsRef::as_ref).collect::<Vec<_>>();
+                let rs = results.iter().map(AsRef::as_ref).collect::<Vec<_>>();
+                write!(f, "func ({}) -> ({})", ps.join(", "), rs.join(", "))
+            }
+            ExternType::Global { ty, mutable } => {
+                write!(
+                    f,
+                    "{} global of type {}",
+                    if *mutable { "mutable" } else { "immutable" },
+                    ty
+                )
+            }
+            ExternType::Memory { min, max } => write!(f, "memory {}..{}", min, Opt(*max)),
+            ExternType::Table { min, max } => write!(f, "table {}..{}", min, Opt(*max)),
+        }
+    }
+}
+
+struct Opt(Option<u32>);
+impl fmt::Display for Opt {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.0 {
+            Some(n) => write!(f, "{}", n),
+            None => write!(f, "unbounded"),
+        }
+    }
+}
+
+// A description of the host's import environment: for every `(module,
+// name)` pair the host is willing to provide, the `ExternType` it will
+// satisfy the import with. The validator consults this (via `resolve` and
+// `validate_imports`) instead of hardcoding a single `"env"."print"`
+// import, the way `wasmi`'s `ImportsBuilder` resolves imports against a
+// host-supplied module. Building the `ImportEnv` from a host embedding's
+// declared exports, and plumbing it into per-function validation, is the
+// caller's responsibility and lives in the instruction-sequence validator
+// alongside the rest of module-level checking.
+#[derive(Default)]
+pub struct ImportEnv {
+    types: HashMap<String, HashMap<String, ExternType>>,
+}
+
+impl ImportEnv {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn register(
+        &mut self,
+        mod_name: impl Into<String>,
+        name: impl Into<String>,
+        ty: ExternType,
+    ) {
+        self.types
+            .entry(mod_name.into())
+            .or_default()
+            .insert(name.into(), ty);
+    }
+
+    pub fn lookup(&self, mod_name: &str, name: &str) -> Option<&ExternType> {
+        self.types.get(mod_name)?.get(name)
+    }
+
+    // All `(module, name)` pairs currently registered, sorted for stable
+    // diagnostics, for listing when a module imports something the host
+    // doesn't provide.
+    pub fn names(&self) -> Vec<(&str, &str)> {
+        let mut names: Vec<(&str, &str)> = self
+            .types
+            .iter()
+            .flat_map(|(m, ns)| ns.keys().map(move |n| (m.as_str(), n.as_str())))
+            .collect();
+        names.sort_unstable();
+        names
+    }
+
+    // Resolve a module's declared import against this host environment: an
+    // `Err(UnknownImport)` when the host has nothing registered for
+    // `(mod_name, name)`, an `Err(ImportTypeMismatch)` when it does but the
+    // declared type disagrees with what the host provides, or `Ok(())` when
+    // the import is satisfied.
+    pub fn resolve<'a>(
+        &self,
+        mod_name: &str,
+        name: &str,
+        declared: &ExternType,
+        offset: usize,
+        source: &'a str,
+    ) -> Result<'a, ()> {
+        match self.lookup(mod_name, name) {
+            None => Err(Error::new(
+                ErrorKind::UnknownImport {
+                    mod_name: mod_name.to_string(),
+                    name: name.to_string(),
+                    known: self
+                        .names()
+                        .into_iter()
+                        .map(|(m, n)| (m.to_string(), n.to_string()))
+                        .collect(),
+                },
+                offset,
+                source,
+            )),
+            Some(expected) if expected != declared => Err(Error::new(
+                ErrorKind::ImportTypeMismatch {
+                    mod_name: mod_name.to_string(),
+                    name: name.to_string(),
+                    expected: expected.clone(),
+                    actual: declared.clone(),
+                },
+                offset,
+                source,
+            )),
+            Some(_) => Ok(()),
+        }
+    }
+}
+
 #[cfg_attr(test, derive(Debug))]
 pub enum ErrorKind {
     IndexOutOfBounds {
@@ -21,6 +168,13 @@ pub enum ErrorKind {
     UnknownImport {
         mod_name: String,
         name: String,
+        known: Vec<(String, String)>,
+    },
+    ImportTypeMismatch {
+        mod_name: String,
+        name: String,
+        expected: ExternType,
+        actual: ExternType,
     },
     TypeMismatch {
         op: &'static str,
@@ -43,6 +197,46 @@ pub enum ErrorKind {
         align: u32,
         bits: u8,
     },
+    ControlStackTooDeep {
+        op: &'static str,
+        depth: usize,
+        limit: usize,
+    },
+    OperandStackTooDeep {
+        op: &'static str,
+        depth: usize,
+        limit: usize,
+    },
+    TooManyLocals {
+        op: &'static str,
+        count: usize,
+        limit: usize,
+    },
+}
+
+impl ErrorKind {
+    // A stable, machine-readable code for this error kind. Tooling should
+    // match on this rather than scraping the `Display` message, which is
+    // free to change wording across versions.
+    pub fn code(&self) -> &'static str {
+        use ErrorKind::*;
+        match self {
+            IndexOutOfBounds { .. } => "index-out-of-bounds",
+            MultipleReturnTypes(..) => "multiple-return-types",
+            TooFewFuncLocalsForParams { .. } => "too-few-func-locals-for-params",
+            ParamTypeMismatchWithLocal { .. } => "param-type-mismatch-with-local",
+            UnknownImport { .. } => "unknown-import",
+            ImportTypeMismatch { .. } => "import-type-mismatch",
+            TypeMismatch { .. } => "type-mismatch",
+            CtrlFrameEmpty { .. } => "ctrl-frame-empty",
+            LabelStackEmpty { .. } => "label-stack-empty",
+            SetImmutableGlobal { .. } => "set-immutable-global",
+            TooLargeAlign { .. } => "too-large-align",
+            ControlStackTooDeep { .. } => "control-stack-too-deep",
+            OperandStackTooDeep { .. } => "operand-stack-too-deep",
+            TooManyLocals { .. } => "too-many-locals",
+        }
+    }
 }
 
 #[cfg_attr(test, derive(Debug))]
@@ -91,21 +285,41 @@ impl<'a> fmt::Display for Error<'a> {
                 Ordinal(*idx),
                 local
             )?,
-            UnknownImport { mod_name, name } => {
-                if *mod_name != "env" {
+            UnknownImport {
+                mod_name,
+                name,
+                known,
+            } => {
+                if known.is_empty() {
                     write!(
                         f,
-                        "unknown module name '{}'. valid module name is currently only 'env'",
-                        mod_name
+                        "unknown import '{}.{}'. no imports are registered in the host environment",
+                        mod_name, name
                     )?
                 } else {
+                    let names = known
+                        .iter()
+                        .map(|(m, n)| format!("'{}.{}'", m, n))
+                        .collect::<Vec<_>>();
                     write!(
                         f,
-                        "no exported name '{}' in module 'env'. currently only 'print' is exported",
-                        name
+                        "unknown import '{}.{}'. valid imports are: {}",
+                        mod_name,
+                        name,
+                        names.join(", ")
                     )?
                 }
             }
+            ImportTypeMismatch {
+                mod_name,
+                name,
+                expected,
+                actual,
+            } => write!(
+                f,
+                "type of import '{}.{}' does not match: expected {} but got {}",
+                mod_name, name, expected, actual
+            )?,
             TypeMismatch {
                 op,
                 expected,
@@ -131,25 +345,55 @@ impl<'a> fmt::Display for Error<'a> {
             LabelStackEmpty { op } => write!(f, "label stack for control instructions is unexpectedly empty at '{}' instruction", op)?,
             SetImmutableGlobal{ ty, idx } => write!(f, "{} value cannot be set to immutable global variable {}", ty, idx)?,
             TooLargeAlign { align, bits } => write!(f, "align {} must not be larger than {}bits / 8", align, bits)?,
+            ControlStackTooDeep { op, depth, limit } => write!(
+                f,
+                "control frame stack is too deep at '{}' instruction: depth {} exceeds the limit of {}",
+                op, depth, limit
+            )?,
+            OperandStackTooDeep { op, depth, limit } => write!(
+                f,
+                "operand stack is too deep at '{}' instruction: depth {} exceeds the limit of {}",
+                op, depth, limit
+            )?,
+            TooManyLocals { op, count, limit } => write!(
+                f,
+                "too many locals for function containing '{}' instruction: {} locals exceeds the limit of {}",
+                op, count, limit
+            )?,
         }
 
-        if self.offset == self.source.len() {
-            write!(f, " caused at byte offset {} (end of input)", self.offset)
-        } else {
-            let source = &self.source[self.offset..];
-            let end = source
-                .find(['\n', '\r'].as_ref())
-                .unwrap_or_else(|| source.len());
-            write!(
+        let (line, column) = self.line_column();
+
+        match self.snippet() {
+            None => write!(
+                f,
+                " caused at byte offset {} ({}:{}) (end of input)",
+                self.offset, line, column
+            ),
+            Some(line_text) => write!(
                 f,
-                " caused at byte offset {}\n\n ... {}\n     ^\n     starts from here",
+                " caused at byte offset {} ({}:{})\n\n {}\n {}^\n starts from here",
                 self.offset,
-                &source[..end],
-            )
+                line,
+                column,
+                line_text,
+                " ".repeat(self.caret_column()),
+            ),
         }
     }
 }
 
+// Clamp `offset` down to the nearest UTF-8 character boundary in `s`, so that
+// slicing `s` at the returned index never panics even when `offset` was
+// computed from unrelated (e.g. binary) coordinates.
+fn floor_char_boundary(s: &str, offset: usize) -> usize {
+    let mut offset = offset.min(s.len());
+    while offset > 0 && !s.is_char_boundary(offset) {
+        offset -= 1;
+    }
+    offset
+}
+
 impl<'a> Error<'a> {
     pub(crate) fn new(kind: ErrorKind, offset: usize, source: &'a str) -> Box<Self> {
         Box::new(Self {
@@ -158,6 +402,247 @@ impl<'a> Error<'a> {
             offset,
         })
     }
+
+    // 1-based (line, column) of `self.offset`, clamped to the nearest
+    // character boundary so it's always safe to compute even when `offset`
+    // was derived from unrelated (e.g. binary) coordinates.
+    fn line_column(&self) -> (usize, usize) {
+        let offset = floor_char_boundary(self.source, self.offset);
+        let line_start = self.source[..offset].rfind('\n').map_or(0, |i| i + 1);
+        let line = self.source[..line_start].matches('\n').count() + 1;
+        let column = offset - line_start + 1;
+        (line, column)
+    }
+
+    // Number of *characters* (not bytes) between the start of the offending
+    // line and `self.offset`, for padding the `^` caret so it lines up under
+    // the right character even when the line contains multibyte UTF-8.
+    fn caret_column(&self) -> usize {
+        let offset = floor_char_boundary(self.source, self.offset);
+        let line_start = self.source[..offset].rfind('\n').map_or(0, |i| i + 1);
+        self.source[line_start..offset].chars().count()
+    }
+
+    // The full text of the source line containing `self.offset`, or `None`
+    // when the offset points past the end of the input.
+    fn snippet(&self) -> Option<&'a str> {
+        if self.offset >= self.source.len() {
+            return None;
+        }
+        let offset = floor_char_boundary(self.source, self.offset);
+        let line_start = self.source[..offset].rfind('\n').map_or(0, |i| i + 1);
+        let line_end = self.source[offset..]
+            .find(['\n', '\r'].as_ref())
+            .map_or(self.source.len(), |i| offset + i);
+        Some(&self.source[line_start..line_end])
+    }
+
+    // Render this error as a single-line JSON object with the fields
+    // `code`, `message`, `offset`, `line`, `column` and `snippet`, for
+    // tooling that wants a stable, parseable diagnostic instead of scraping
+    // the `Display` text.
+    pub fn to_json(&self) -> String {
+        let (line, column) = self.line_column();
+        let message = self.to_string();
+        let mut json = String::from("{\"code\":");
+        json_push_str(&mut json, self.kind.code());
+        json.push_str(",\"message\":");
+        json_push_str(&mut json, &message);
+        json.push_str(&format!(",\"offset\":{}", self.offset));
+        json.push_str(&format!(",\"line\":{}", line));
+        json.push_str(&format!(",\"column\":{}", column));
+        json.push_str(",\"snippet\":");
+        match self.snippet() {
+            Some(s) => json_push_str(&mut json, s),
+            None => json.push_str("null"),
+        }
+        json.push('}');
+        json
+    }
+}
+
+// Append `s` to `out` as a quoted, escaped JSON string literal.
+fn json_push_str(out: &mut String, s: &str) {
+    out.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c if (c as u32) < 0x20 => out.push_str(&format!("\\u{:04x}", c as u32)),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
 }
 
 pub type Result<'a, T> = ::std::result::Result<T, Box<Error<'a>>>;
+
+// A collection of validation errors gathered from a single pass over a
+// module. Unlike `Result`, which stops at the first `Error`, `Errors` lets a
+// validator keep going and report every problem it finds in one run.
+#[cfg_attr(test, derive(Debug))]
+pub struct Errors<'a>(Vec<Box<Error<'a>>>);
+
+impl<'a> Errors<'a> {
+    pub fn new() -> Self {
+        Errors(Vec::new())
+    }
+
+    pub fn push(&mut self, err: Box<Error<'a>>) {
+        self.0.push(err);
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+}
+
+impl<'a> Default for Errors<'a> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<'a> fmt::Display for Errors<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (i, err) in self.0.iter().enumerate() {
+            if i > 0 {
+                write!(f, "\n\n")?;
+            }
+            write!(f, "{}", err)?;
+        }
+        Ok(())
+    }
+}
+
+// Validate every import a module declares against `env`, accumulating an
+// `UnknownImport` or `ImportTypeMismatch` for each one that doesn't resolve
+// instead of stopping at the first. `imports` is `(mod_name, name,
+// declared type, byte offset of the import declaration)` for each import
+// in the module, in declaration order.
+//
+// This only covers import resolution. It is the `Errors`-accumulating
+// counterpart of `ImportEnv::resolve`, not a full-module driver: other
+// per-instruction diagnostics (`TypeMismatch`, `IndexOutOfBounds`,
+// `SetImmutableGlobal`, ...) are produced by the instruction-sequence
+// validator and are out of scope here.
+pub fn validate_imports<'a>(
+    env: &ImportEnv,
+    imports: &[(String, String, ExternType, usize)],
+    source: &'a str,
+) -> ::std::result::Result<(), Errors<'a>> {
+    let mut errors = Errors::new();
+    for (mod_name, name, declared, offset) in imports {
+        if let Err(err) = env.resolve(mod_name, name, declared, *offset, source) {
+            errors.push(err);
+        }
+    }
+    if errors.is_empty() {
+        Ok(())
+    } else {
+        Err(errors)
+    }
+}
+
+// Configurable structural limits for validating a single function body,
+// guarding against pathological or adversarial modules that would otherwise
+// drive the validator (and later the interpreter) into unbounded stack
+// growth, the way wasmi propagates an error as soon as its frame stack would
+// overflow rather than growing it without bound. The instruction-sequence
+// validator is expected to hold a `Limits` (or `Limits::default()`) and call
+// `check_control_depth`/`check_operand_depth`/`check_locals_count` as it
+// walks control frames, operand pushes, and local declarations respectively;
+// that call-site lives in the instruction validator, not in this module.
+#[cfg_attr(test, derive(Debug, Clone, Copy, PartialEq))]
+pub struct Limits {
+    pub max_control_depth: usize,
+    pub max_operand_depth: usize,
+    pub max_locals: usize,
+}
+
+impl Default for Limits {
+    fn default() -> Self {
+        Self {
+            max_control_depth: 1024,
+            max_operand_depth: 65536,
+            max_locals: 50_000,
+        }
+    }
+}
+
+impl Limits {
+    // Check the control-frame nesting depth reached while validating `op`,
+    // returning `ControlStackTooDeep` once `depth` exceeds `max_control_depth`.
+    pub fn check_control_depth<'a>(
+        &self,
+        op: &'static str,
+        depth: usize,
+        offset: usize,
+        source: &'a str,
+    ) -> Result<'a, ()> {
+        if depth > self.max_control_depth {
+            Err(Error::new(
+                ErrorKind::ControlStackTooDeep {
+                    op,
+                    depth,
+                    limit: self.max_control_depth,
+                },
+                offset,
+                source,
+            ))
+        } else {
+            Ok(())
+        }
+    }
+
+    // Check the operand-stack depth reached while validating `op`,
+    // returning `OperandStackTooDeep` once `depth` exceeds `max_operand_depth`.
+    pub fn check_operand_depth<'a>(
+        &self,
+        op: &'static str,
+        depth: usize,
+        offset: usize,
+        source: &'a str,
+    ) -> Result<'a, ()> {
+        if depth > self.max_operand_depth {
+            Err(Error::new(
+                ErrorKind::OperandStackTooDeep {
+                    op,
+                    depth,
+                    limit: self.max_operand_depth,
+                },
+                offset,
+                source,
+            ))
+        } else {
+            Ok(())
+        }
+    }
+
+    // Check the number of locals declared by a function containing `op`,
+    // returning `TooManyLocals` once `count` exceeds `max_locals`.
+    pub fn check_locals_count<'a>(
+        &self,
+        op: &'static str,
+        count: usize,
+        offset: usize,
+        source: &'a str,
+    ) -> Result<'a, ()> {
+        if count > self.max_locals {
+            Err(Error::new(
+                ErrorKind::TooManyLocals {
+                    op,
+                    count,
+                    limit: self.max_locals,
+                },
+                offset,
+                source,
+            ))
+        } else {
+            Ok(())
+        }
+    }
+}